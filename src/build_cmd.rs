@@ -1,8 +1,10 @@
 extern crate cargo_metadata;
+extern crate serde_json;
 
 use cmake_config::{Key, SimpleFlag};
 use command_ext::CommandExt;
-use fel4_config::{FlatTomlValue, SupportedTarget};
+use fel4_config::{BootstrapMethod, FlatTomlValue, SupportedTarget, Target, ToolchainConfig};
+use serde_json::json;
 use std::borrow::Borrow;
 use std::collections::HashSet;
 use std::env::{self, current_dir};
@@ -12,8 +14,9 @@ use std::process::Command;
 
 use super::Error;
 use cmake_codegen::{cache_to_interesting_flags, truthy_boolean_flags_as_rust_identifiers};
-use config::{get_resolved_config, BuildCmd, Fel4BuildProfile, ResolvedConfig};
+use config::{get_resolved_config, BuildCmd, Fel4BuildProfile, ReconciliationPolicy, ResolvedConfig};
 use generator::Generator;
+use step::{write_fingerprint_if_changed, Step};
 
 pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
     let build_profile = Fel4BuildProfile::from(subcmd);
@@ -32,10 +35,24 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
 
     info!("\ntarget build cache: {:?}", target_build_cache_path,);
 
+    // A `Target::Custom` spec lives wherever the user put it, so
+    // `RUST_TARGET_PATH` must point at its parent directory rather than the
+    // crate's bundled `target_specs_path`.
+    let rust_target_path = match config.fel4_config.target {
+        Target::Custom(ref spec) => match spec.path.parent() {
+            // A bare relative filename (e.g. `target = "my-spec.json"`)
+            // yields `Some("")`, not `None` -- treat that the same as "no
+            // parent" and fall back to `root_dir`.
+            Some(parent) if !parent.as_os_str().is_empty() => PathBuf::from(parent),
+            _ => config.root_dir.clone(),
+        },
+        Target::Known(_) => config.root_dir.join(&config.fel4_config.target_specs_path),
+    };
+
     let cross_layer_locations = CrossLayerLocations {
         fel4_artifact_path: config.root_dir.join(&artifact_path),
         fel4_manifest_path: config.root_dir.join("fel4.toml"),
-        rust_target_path: config.root_dir.join(&config.fel4_config.target_specs_path),
+        rust_target_path,
     };
 
     let fel4_flags: Vec<SimpleFlag> = config
@@ -53,8 +70,12 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
             }
         })
         .collect();
+    let toolchain_override = config.fel4_config.toolchain.for_target(&config.fel4_config.target);
+
+    let truthy_flag_identifiers = truthy_boolean_flags_as_rust_identifiers(&fel4_flags)?;
     let rustflags_env_var = merge_feature_flags_with_rustflags_env_var(
-        &truthy_boolean_flags_as_rust_identifiers(&fel4_flags)?,
+        &truthy_flag_identifiers,
+        toolchain_override.and_then(|t| t.linker.as_ref()),
     );
 
     // Generate the source code entry point (root task) for the application
@@ -89,47 +110,60 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
     let kernel_path = artifact_path.join("kernel");
     fs::create_dir_all(&artifact_path)?;
 
-    // For ARM targets, we currently take advantage of the
-    // seL4 elfloader-tool to bootstrap the system and kick
-    // things off.
-    // To accomplish this, we just re-build libsel4-sys
-    // with an extra environment variable which gives
-    // elfloader-tool a path to the root-task binary
-    match config.fel4_config.target {
-        SupportedTarget::Armv7Sel4Fel4 => {
-            construct_libsel4_build_command(subcmd, &config, &cross_layer_locations)
-                .env(
-                    "FEL4_ROOT_TASK_IMAGE_PATH",
-                    target_build_cache_path.join("root-task"),
-                )
-                .env("RUSTFLAGS", &rustflags_env_var)
-                .run_cmd()?;
-
-            // seL4 CMake rules will just output everything to `kernel`
-            // we copy it so it's consistent with our image name but
-            // won't trigger a rebuild (as it would if we were to move it)
-            fs::copy(&kernel_path, &sysimg_path)?;
-        }
-        SupportedTarget::Aarch64Sel4Fel4 => {
-            construct_libsel4_build_command(subcmd, &config, &cross_layer_locations)
-                .env(
-                    "FEL4_ROOT_TASK_IMAGE_PATH",
-                    target_build_cache_path.join("root-task"),
-                )
-                .env("RUSTFLAGS", &rustflags_env_var)
-                .run_cmd()?;
-
-            // seL4 CMake rules will just output everything to `kernel`
-            // we copy it so it's consistent with our image name but
-            // won't trigger a rebuild (as it would if we were to move it)
-            fs::copy(&kernel_path, &sysimg_path)?;
+    // Some targets (historically just ARM/AArch64, but now any target whose
+    // spec declares it) take advantage of the seL4 elfloader-tool to
+    // bootstrap the system and kick things off.
+    // To accomplish this, we re-build libsel4-sys with an extra environment
+    // variable which gives elfloader-tool a path to the root-task binary.
+    // Which bootstrap method applies is driven by the target itself (the
+    // `SupportedTarget` enum for built-in targets, or the
+    // `bootstrap-method` fel4 metadata field for a custom JSON target spec)
+    // rather than matching on a fixed enum here.
+    match config.fel4_config.target.bootstrap_method() {
+        BootstrapMethod::ElfLoader => {
+            let root_task_binary_path = target_build_cache_path.join("root-task");
+
+            // The embed step also depends on the resolved fel4 flags (they
+            // get baked into libsel4-sys' build), which aren't backed by a
+            // file of their own; fingerprint them into one so a flag change
+            // is visible to the step as a changed input.
+            let flags_fingerprint_path = target_build_cache_path.join(".fel4-flags-fingerprint");
+            let flags_fingerprint = {
+                let mut entries: Vec<String> =
+                    fel4_flags.iter().map(|f| format!("{:?}", f)).collect();
+                entries.sort();
+                entries.join("\n")
+            };
+            write_fingerprint_if_changed(&flags_fingerprint_path, &flags_fingerprint)?;
+
+            Step {
+                name: "embed-root-task-into-elfloader",
+                inputs: vec![root_task_binary_path, flags_fingerprint_path],
+                output: sysimg_path.clone(),
+                run: Box::new(|| {
+                    construct_libsel4_build_command(subcmd, &config, &cross_layer_locations)
+                        .env(
+                            "FEL4_ROOT_TASK_IMAGE_PATH",
+                            target_build_cache_path.join("root-task"),
+                        )
+                        .env("RUSTFLAGS", &rustflags_env_var)
+                        .run_cmd()?;
+
+                    // seL4 CMake rules will just output everything to `kernel`
+                    // we copy it so it's consistent with our image name but
+                    // won't trigger a rebuild (as it would if we were to move it)
+                    fs::copy(&kernel_path, &sysimg_path)?;
+                    Ok(())
+                }),
+            }.run_if_stale()?;
         }
-        _ => {
+        BootstrapMethod::Direct => {
             fs::copy(target_build_cache_path.join("root-task"), &sysimg_path)?;
         }
     }
 
-    {
+    let simple_fel4_flags: HashSet<SimpleFlag> = fel4_flags.into_iter().collect();
+    let cmake_verified_flags: HashSet<SimpleFlag> = {
         // Extract the resolved CMake config details and filter down to ones that might
         // be useful for cross-reference with the fel4-config derived values
         let interesting_raw_flags_from_cmake = cache_to_interesting_flags(
@@ -139,28 +173,51 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
             .iter()
             .map(SimpleFlag::from)
             .collect();
-        let simple_fel4_flags: HashSet<SimpleFlag> = fel4_flags.into_iter().collect();
         if !&simple_fel4_flags.is_subset(&simple_cmake_flags) {
+            let mut mismatches = Vec::new();
             for s in &simple_fel4_flags {
                 if simple_cmake_flags.contains(s) {
                     continue;
                 }
-                println!("Found a fel4 flag {:?} that was not in the cmake flags", s);
                 let key = match s {
                     SimpleFlag::Boolish(Key(k), _) | SimpleFlag::Stringish(Key(k), _) => k.clone(),
                 };
+                let mut description =
+                    format!("fel4 flag {:?} was not found (with that value) in CMakeCache.txt", s);
                 for raw_flag in &interesting_raw_flags_from_cmake {
                     if raw_flag.key == key {
-                        println!(
-                            "    But there was a flag with the same key in CMakeCache.txt: {:?}",
+                        description.push_str(&format!(
+                            " (CMakeCache.txt has the same key with a different value: {:?})",
                             raw_flag
-                        );
+                        ));
+                    }
+                }
+                mismatches.push(description);
+            }
+
+            match subcmd.reconciliation_policy {
+                ReconciliationPolicy::Strict => {
+                    for m in &mismatches {
+                        println!("{}", m);
+                    }
+                    return Err(Error::ConfigError("Unexpected mismatch between the fel4.toml config values and seL4's CMakeCache.txt config values".to_string()));
+                }
+                ReconciliationPolicy::Warn => {
+                    for m in &mismatches {
+                        warn!("{}", m);
+                    }
+                }
+                ReconciliationPolicy::Sync => {
+                    // fel4.toml is authoritative; just surface the drift so
+                    // the user can see what CMake disagreed on.
+                    for m in &mismatches {
+                        warn!("treating fel4.toml as authoritative over CMakeCache.txt: {}", m);
                     }
                 }
             }
-            return Err(Error::ConfigError("Unexpected mismatch between the fel4.toml config values and seL4's CMakeCache.txt config values".to_string()));
         }
-    }
+        simple_fel4_flags.intersection(&simple_cmake_flags).cloned().collect()
+    };
 
     if !sysimg_path.exists() {
         return Err(Error::ConfigError(format!(
@@ -181,9 +238,106 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
     info!("kernel: '{}'", kernel_path.display());
     info!("feL4img: '{}'", sysimg_path.display());
 
+    write_build_manifest(
+        artifact_path,
+        &BuildManifest {
+            target: config.fel4_config.target.full_name(),
+            build_profile: build_profile.as_fel4_config_build_profile().full_name(),
+            kernel_path: kernel_path.clone(),
+            fel4img_path: sysimg_path.clone(),
+            truthy_flag_identifiers,
+            fel4_flags: simple_fel4_flags.iter().map(FlagManifestEntry::from).collect(),
+            cmake_verified_flags: cmake_verified_flags
+                .iter()
+                .map(FlagManifestEntry::from)
+                .collect(),
+        },
+    )?;
+
     Ok(())
 }
 
+/// A single resolved fel4 flag, reshaped for JSON output.
+struct FlagManifestEntry {
+    key: String,
+    value: FlagValueManifest,
+}
+
+enum FlagValueManifest {
+    Bool(bool),
+    Str(String),
+}
+
+impl<'a> From<&'a SimpleFlag> for FlagManifestEntry {
+    fn from(flag: &'a SimpleFlag) -> Self {
+        match flag {
+            SimpleFlag::Boolish(Key(k), b) => FlagManifestEntry {
+                key: k.clone(),
+                value: FlagValueManifest::Bool(*b),
+            },
+            SimpleFlag::Stringish(Key(k), s) => FlagManifestEntry {
+                key: k.clone(),
+                value: FlagValueManifest::Str(s.clone()),
+            },
+        }
+    }
+}
+
+impl FlagManifestEntry {
+    fn to_json(&self) -> serde_json::Value {
+        let value = match self.value {
+            FlagValueManifest::Bool(b) => serde_json::Value::Bool(b),
+            FlagValueManifest::Str(ref s) => serde_json::Value::String(s.clone()),
+        };
+        json!({ "key": self.key, "value": value })
+    }
+}
+
+/// A machine-readable description of what `handle_build_cmd` produced,
+/// written out as `fel4-build-manifest.json` alongside the kernel and
+/// feL4img so downstream tooling (simulators, CI, flashers, test
+/// harnesses) can locate images and reason about enabled features without
+/// scraping log lines.
+struct BuildManifest {
+    target: String,
+    build_profile: String,
+    kernel_path: PathBuf,
+    fel4img_path: PathBuf,
+    /// The `--cfg feature="..."` identifiers threaded into RUSTFLAGS
+    truthy_flag_identifiers: Vec<String>,
+    /// All resolved fel4.toml flags (both booleans and raw key/value pairs)
+    fel4_flags: Vec<FlagManifestEntry>,
+    /// The subset of `fel4_flags` that was cross-checked as present (with
+    /// the same value) in CMakeCache.txt
+    cmake_verified_flags: Vec<FlagManifestEntry>,
+}
+
+impl BuildManifest {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "target": self.target,
+            "build_profile": self.build_profile,
+            "kernel_path": self.kernel_path,
+            "fel4img_path": self.fel4img_path,
+            "truthy_flag_identifiers": self.truthy_flag_identifiers,
+            "fel4_flags": self.fel4_flags.iter().map(FlagManifestEntry::to_json).collect::<Vec<_>>(),
+            "cmake_verified_flags": self.cmake_verified_flags.iter().map(FlagManifestEntry::to_json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn write_build_manifest(artifact_path: &Path, manifest: &BuildManifest) -> Result<(), Error> {
+    let manifest_path = artifact_path.join("fel4-build-manifest.json");
+    let manifest_file = File::create(&manifest_path).map_err(|e| {
+        Error::IO(format!(
+            "Could not create build manifest file '{:?}': {}",
+            &manifest_path, e
+        ))
+    })?;
+    serde_json::to_writer_pretty(manifest_file, &manifest.to_json())
+        .map_err(|e| Error::IO(format!("Could not write build manifest: {}", e)))
+}
+
 fn is_current_dir_root_dir<P: AsRef<Path>>(root_dir: P) -> Result<bool, ::std::io::Error> {
     let root_dir_buf: PathBuf = root_dir.as_ref().into();
     Ok(canonicalize(root_dir_buf)? == canonicalize(current_dir()?)?)
@@ -205,7 +359,7 @@ where
         .arg(&subcmd.cargo_manifest_path)
         .arg_if(|| subcmd.release, "--release")
         .add_loudness_args(&subcmd.loudness)
-        .handle_arm_edge_case(&config.fel4_config.target)
+        .apply_toolchain_overrides(&config.fel4_config.target, &config.fel4_config.toolchain)
         .add_locations_as_env_vars(locations)
         .arg("--target")
         .arg(&config.fel4_config.target.full_name())
@@ -240,7 +394,7 @@ where
         .arg(&subcmd.cargo_manifest_path)
         .arg_if(|| subcmd.release, "--release")
         .add_loudness_args(&subcmd.loudness)
-        .handle_arm_edge_case(&config.fel4_config.target)
+        .apply_toolchain_overrides(&config.fel4_config.target, &config.fel4_config.toolchain)
         .arg_if(|| subcmd.tests, "--features")
         .arg_if(|| subcmd.tests, "test alloc")
         .arg("--target")
@@ -272,8 +426,17 @@ where
         cross_layer_locations: &'l CrossLayerLocations<P>,
     ) -> &'c mut Self;
 
-    /// Handle a possible edge case in cross-compiling for arm
-    fn handle_arm_edge_case<'c, 'f>(&'c mut self, config: &'f SupportedTarget) -> &'c mut Self;
+    /// Apply any user-specified `[toolchain]` cc/cxx/ar overrides for the
+    /// given target as `CC_`/`CXX_`/`AR_<triple>` environment variables.
+    /// When no `cc` is given, built-in ARM targets fall back to the known
+    /// ARM cross-compiler band-aid; cxx and ar have no fallback. The linker
+    /// override is not applied here: it goes into RUSTFLAGS via
+    /// `merge_feature_flags_with_rustflags_env_var`.
+    fn apply_toolchain_overrides<'c, 'f>(
+        &'c mut self,
+        target: &'f Target,
+        toolchain: &'f ToolchainConfig,
+    ) -> &'c mut Self;
 }
 
 impl BuildCommandExt for Command {
@@ -287,7 +450,21 @@ impl BuildCommandExt for Command {
         self
     }
 
-    fn handle_arm_edge_case<'c, 'f>(&'c mut self, target: &'f SupportedTarget) -> &mut Self {
+    fn apply_toolchain_overrides<'c, 'f>(
+        &'c mut self,
+        target: &'f Target,
+        toolchain: &'f ToolchainConfig,
+    ) -> &'c mut Self {
+        let triple = target.full_name();
+        let over = toolchain.for_target(target);
+
+        if let Some(cxx) = over.and_then(|o| o.cxx.as_ref()) {
+            self.env(format!("CXX_{}", triple), cxx);
+        }
+        if let Some(ar) = over.and_then(|o| o.ar.as_ref()) {
+            self.env(format!("AR_{}", triple), ar);
+        }
+
         // There seems to be an issue with `compiler_builtins` imposing
         // a default compiler used by the `c` feature/dependency; where
         // it no longer picks up a sane cross-compiler (when host != target triple).
@@ -295,7 +472,10 @@ impl BuildCommandExt for Command {
         // host's default compiler (likely x86_64) rather than using
         // what our target specification (or even Xargo.toml) has prescribed.
         //
-        // This fix is a band aid, and will be addressed properly at a later point.
+        // If the user gave us an explicit `cc` in `[toolchain]`, use that.
+        // Otherwise fall back to the known-good ARM cross-compiler band-aid
+        // for built-in ARM targets; custom JSON target specs have no such
+        // fallback and must specify their own `cc` if they need one.
         // However we can still force/control which cross compiler will
         // get used to build the shims through the use of CC's envirnoment
         // variables.
@@ -303,19 +483,28 @@ impl BuildCommandExt for Command {
         // See the following issues:
         // `xargo/issues/216`
         // `cargo-fel4/issues/18`
-        match *target {
-            SupportedTarget::Armv7Sel4Fel4 => {
-                self.env("CC_armv7-sel4-fel4", "arm-linux-gnueabihf-gcc")
-            }
-            SupportedTarget::Aarch64Sel4Fel4 => {
-                self.env("CC_aarch64-sel4-fel4", "aarch64-linux-gnu-gcc")
+        match over.and_then(|o| o.cc.as_ref()) {
+            Some(cc) => {
+                self.env(format!("CC_{}", triple), cc);
+                self
             }
-            _ => self,
+            None => match *target {
+                Target::Known(SupportedTarget::Armv7Sel4Fel4) => {
+                    self.env("CC_armv7-sel4-fel4", "arm-linux-gnueabihf-gcc")
+                }
+                Target::Known(SupportedTarget::Aarch64Sel4Fel4) => {
+                    self.env("CC_aarch64-sel4-fel4", "aarch64-linux-gnu-gcc")
+                }
+                _ => self,
+            },
         }
     }
 }
 
-fn merge_feature_flags_with_rustflags_env_var(feature_flags: &[String]) -> String {
+fn merge_feature_flags_with_rustflags_env_var(
+    feature_flags: &[String],
+    linker: Option<&String>,
+) -> String {
     let mut output: String = match env::var("RUSTFLAGS") {
         Ok(s) => s,
         Err(env::VarError::NotUnicode(_)) => String::new(),
@@ -328,5 +517,8 @@ fn merge_feature_flags_with_rustflags_env_var(feature_flags: &[String]) -> Strin
         output.push_str("--cfg ");
         output.push_str(&format!("feature=\"{}\" ", feature));
     }
+    if let Some(linker) = linker {
+        output.push_str(&format!("-C linker={} ", linker));
+    }
     output
 }
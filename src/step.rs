@@ -0,0 +1,87 @@
+//! A minimal build-step abstraction, modeled loosely on rustbuild's
+//! `Step`/`Builder` design: each step declares the file(s) it depends on and
+//! the file it produces, and is skipped once its output is already at least
+//! as new as every input. This lets cargo-fel4 represent expensive external
+//! build-tool invocations (xargo, the seL4 CMake build) as first-class,
+//! skippable nodes instead of ad-hoc copy/env juggling.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::Error;
+
+/// A single unit of build work with declared inputs and an output.
+pub struct Step<'a> {
+    pub name: &'static str,
+    /// Files this step is considered stale relative to.
+    pub inputs: Vec<PathBuf>,
+    /// The file this step produces.
+    pub output: PathBuf,
+    pub run: Box<dyn FnMut() -> Result<(), Error> + 'a>,
+}
+
+impl<'a> Step<'a> {
+    /// Run this step, unless `output` already exists and is at least as new
+    /// as every file in `inputs` (all of which exist), in which case it's
+    /// skipped as a no-op.
+    pub fn run_if_stale(mut self) -> Result<(), Error> {
+        if self.is_stale()? {
+            info!("build step '{}': stale, running", self.name);
+            (self.run)()
+        } else {
+            info!("build step '{}': inputs unchanged, skipping", self.name);
+            Ok(())
+        }
+    }
+
+    fn is_stale(&self) -> Result<bool, Error> {
+        let output_mtime = match mtime(&self.output)? {
+            Some(t) => t,
+            None => return Ok(true),
+        };
+        for input in &self.inputs {
+            match mtime(input)? {
+                Some(t) if t <= output_mtime => continue,
+                _ => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn mtime(path: &Path) -> Result<Option<SystemTime>, Error> {
+    match fs::metadata(path) {
+        Ok(meta) => meta
+            .modified()
+            .map(Some)
+            .map_err(|e| Error::IO(format!("Could not read mtime of {:?}: {}", path, e))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::IO(format!("Could not stat {:?}: {}", path, e))),
+    }
+}
+
+/// Write `contents` to `path`, but only touch the file (and thus bump its
+/// mtime) if the contents actually differ from what's already on disk.
+///
+/// Build parameters that aren't already file-backed (like the resolved fel4
+/// flags) can be turned into a `Step` input via this function, so a step
+/// only looks "changed" when the parameter actually changed.
+pub fn write_fingerprint_if_changed(path: &Path, contents: &str) -> Result<(), Error> {
+    if let Ok(existing) = fs::read_to_string(path) {
+        if existing == contents {
+            return Ok(());
+        }
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| {
+            Error::IO(format!(
+                "Could not create directory for fingerprint file {:?}: {}",
+                parent, e
+            ))
+        })?;
+    }
+    fs::write(path, contents)
+        .map_err(|e| Error::IO(format!("Could not write fingerprint file {:?}: {}", path, e)))
+}